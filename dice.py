@@ -0,0 +1,63 @@
+import random
+import re
+
+# Dice rolls use the OS entropy source so results can't be predicted or replayed
+dice_random = random.SystemRandom()
+
+# Matches one signed term of a dice expression, e.g. "2d20", "+d6" or "-3"
+DICE_TERM_PATTERN = re.compile(r'\s*([+-]?)\s*(?:(\d*)d(\d+)|(\d+))', re.IGNORECASE)
+MAX_DICE = 100
+MAX_SIDES = 1000
+
+# Function to parse dice notation such as "2d20+5" into (sign, count, sides) dice and a flat modifier
+def parse_dice_notation(notation):
+    expression = notation.strip()
+    dice = []
+    modifier = 0
+    position = 0
+    while position < len(expression):
+        match = DICE_TERM_PATTERN.match(expression, position)
+        # Every term after the first needs an explicit sign
+        if match is None or (position > 0 and not match.group(1)):
+            raise ValueError(f"Invalid dice notation: {notation!r}")
+        sign = -1 if match.group(1) == "-" else 1
+        if match.group(3) is not None:
+            count = int(match.group(2) or 1)
+            sides = int(match.group(3))
+            if not 1 <= count <= MAX_DICE or not 2 <= sides <= MAX_SIDES:
+                raise ValueError(f"Dice must be between 1d2 and {MAX_DICE}d{MAX_SIDES}: {notation!r}")
+            dice.append((sign, count, sides))
+        else:
+            modifier += sign * int(match.group(4))
+        position = match.end()
+
+    if not dice:
+        raise ValueError(f"Dice notation has no dice to roll: {notation!r}")
+    if sum(count for _, count, _ in dice) > MAX_DICE:
+        raise ValueError(f"Cannot roll more than {MAX_DICE} dice at once: {notation!r}")
+    return dice, modifier
+
+# Function to roll a parsed dice expression once, keeping every die result for the breakdown
+def roll_once(dice, modifier, rng):
+    rolls = []
+    total = modifier
+    for sign, count, sides in dice:
+        results = [rng.randint(1, sides) for _ in range(count)]
+        rolls.append({"dice": f"{'-' if sign < 0 else ''}{count}d{sides}", "results": results})
+        total += sign * sum(results)
+    return {"rolls": rolls, "modifier": modifier, "total": total}
+
+# Function to roll dice notation, rolling twice and keeping the higher or lower total for advantage or disadvantage
+def roll_dice(notation, mode="normal", rng=dice_random):
+    if mode not in ("normal", "advantage", "disadvantage"):
+        raise ValueError(f"Unknown roll mode: {mode!r}")
+    dice, modifier = parse_dice_notation(notation)
+
+    attempts = [roll_once(dice, modifier, rng) for _ in range(1 if mode == "normal" else 2)]
+    pick = min if mode == "disadvantage" else max
+    return {
+        "notation": notation,
+        "mode": mode,
+        "attempts": attempts,
+        "total": pick(attempt["total"] for attempt in attempts),
+    }
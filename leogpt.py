@@ -1,11 +1,17 @@
 import discord
 from discord.ext import commands
-from openai import AsyncOpenAI
+from openai import AsyncOpenAI, APIConnectionError, APIStatusError, NotFoundError
+from aiohttp import web
 import logging
 import asyncio
 import time
+import re
+import math
+import random
 from config import config
-from collections import defaultdict
+from redaction import redact_content
+from tools import execute_tool_call, get_tool_definitions
+from collections import defaultdict, deque
 from datetime import datetime, timedelta
 
 # Configuration and Constants
@@ -15,19 +21,245 @@ ASSISTANT_ID = config.assistant_id
 MESSAGE_CHUNK_SIZE = config.message_chunk_size
 THREAD_INACTIVITY_TIMEOUT_HOURS = config.thread_inactivity_timeout_hours
 
+# Optional settings, defaulting to the behavior from before each was introduced
+OPENAI_MAX_RETRIES = getattr(config, "openai_max_retries", 2)
+REDACT_CONTENT = getattr(config, "redact_content", False)
+DM_MODE_ENABLED = getattr(config, "dm_mode_enabled", False)
+USER_REQUESTS_PER_HOUR = getattr(config, "user_requests_per_hour", None)
+GUILD_REQUESTS_PER_HOUR = getattr(config, "guild_requests_per_hour", None)
+MAINTENANCE_MODE = getattr(config, "maintenance_mode", False)
+TEMPERATURE = getattr(config, "temperature", None)
+TOP_P = getattr(config, "top_p", None)
+LOG_LEVELS = getattr(config, "log_levels", {})
+DIRECTIVE_ROLE_NAMES = getattr(config, "directive_role_names", [])
+RUN_RETRIES = getattr(config, "run_retries", 2)
+FALLBACK_MODELS = getattr(config, "fallback_models", [])
+HTTP_PORT = getattr(config, "http_port", None)
+LOCAL_TOOLS = getattr(config, "local_tools", [])
+AUTO_THREAD_ENABLED = getattr(config, "auto_thread_enabled", False)
+AUTO_THREAD_MESSAGES_PER_MINUTE = getattr(config, "auto_thread_messages_per_minute", 20)
+DELETE_ORPHANED_REPLIES = getattr(config, "delete_orphaned_replies", False)
+REGENERATE_ON_EDIT = getattr(config, "regenerate_on_edit", False)
+REPLY_TRACKING_SECONDS = getattr(config, "reply_tracking_seconds", 600)
+REGENERATE_REACTION_ENABLED = getattr(config, "regenerate_reaction_enabled", False)
+REGENERATE_TEMPERATURE = getattr(config, "regenerate_temperature", None)
+CONTEXT_WINDOW_TOKENS = getattr(config, "context_window_tokens", None)
+CONTEXT_WARNING_RATIO = getattr(config, "context_warning_ratio", 0.8)
+MATCH_USER_LANGUAGE = getattr(config, "match_user_language", False)
+CHALLENGE_ACCOUNT_AGE_DAYS = getattr(config, "challenge_account_age_days", None)
+# Per-guild overrides of the settings above, e.g. {123456789012345678: {"match_user_language": True}}
+GUILD_SETTINGS = getattr(config, "guild_settings", {})
+
+# Instruction added to runs so the assistant answers in the language it was asked in
+MATCH_USER_LANGUAGE_INSTRUCTIONS = "Detect the language of the user's latest message and reply in that language, unless they ask for another one."
+
+# Run statuses after which a run will make no further progress
+TERMINAL_RUN_STATUSES = {"completed", "failed", "expired", "cancelled", "incomplete"}
+# Run error codes worth retrying on the same model
+TRANSIENT_RUN_ERROR_CODES = {"rate_limit_exceeded", "server_error"}
+
+# Function to read a setting for a guild, falling back to the global value outside guilds or without an override
+def get_guild_setting(guild, name, default):
+    if guild is None:
+        return default
+    return GUILD_SETTINGS.get(guild.id, {}).get(name, default)
+
 # Setting up logging
 logging.basicConfig(level=logging.INFO, format='%(asctime)s - %(levelname)s - %(message)s')
+for logger_name, level in LOG_LEVELS.items():
+    logging.getLogger(logger_name).setLevel(level)
 
-# OpenAI Client Setup
-openai_client = AsyncOpenAI(api_key=OPENAI_API_KEY)
+# OpenAI Client Setup (retries 429/5xx and connection errors with exponential backoff)
+openai_client = AsyncOpenAI(api_key=OPENAI_API_KEY, max_retries=OPENAI_MAX_RETRIES)
+# The assistant's own tools, loaded at startup when local tools are enabled
+assistant_tools = None
 
 # Discord Bot Setup
 intents = discord.Intents.default()
 bot = commands.Bot(command_prefix="!", intents=intents)
 
+# Matches :emoji_name: tokens that aren't already part of a <:name:id> emoji mention
+EMOJI_TOKEN_PATTERN = re.compile(r'(?<![<\w]):(\w{2,32}):')
+# Splits text around fenced code blocks (closed or not) and inline code spans, keeping them as odd entries
+CODE_SPAN_PATTERN = re.compile(r'(```.*?(?:```|$)|`[^`\n]+`)', re.DOTALL)
+
+# Matches one leading inline directive such as "!model:gpt-4o", "!notools", or "!model:default" and "!tools" to reset them
+DIRECTIVE_PATTERN = re.compile(r'\s*!(model:\S+|notools|tools)(?=\s|$)')
+# Matches user, role and channel mention tokens such as <@123>, <@!123>, <@&123> and <#123>
+MENTION_TOKEN_PATTERN = re.compile(r'<(@[!&]?|#)(\d{15,20})>')
+
 # Thread Management
 thread_ids = defaultdict(lambda: {"thread_id": None, "last_used": datetime.now()})
 
+# Rate Limiting (token buckets keyed by ("user", id) or ("guild", id))
+request_buckets = {}
+
+# Function to refill a bucket based on the time elapsed since its last refill
+def refill_request_bucket(key, requests_per_hour):
+    now = time.time()
+    bucket = request_buckets.setdefault(key, {"tokens": requests_per_hour, "capacity": requests_per_hour, "last_refill": now})
+    refill = (now - bucket["last_refill"]) * requests_per_hour / 3600
+    bucket["tokens"] = min(requests_per_hour, bucket["tokens"] + refill)
+    bucket["last_refill"] = now
+    return bucket
+
+# Function to check the per-user and per-guild request quotas, returning the exhausted scope if any
+def check_rate_limits(message):
+    buckets = []
+    if USER_REQUESTS_PER_HOUR is not None:
+        buckets.append(("user", refill_request_bucket(("user", message.author.id), USER_REQUESTS_PER_HOUR)))
+    if GUILD_REQUESTS_PER_HOUR is not None and message.guild is not None:
+        buckets.append(("guild", refill_request_bucket(("guild", message.guild.id), GUILD_REQUESTS_PER_HOUR)))
+
+    for scope, bucket in buckets:
+        if bucket["tokens"] < 1:
+            return scope
+
+    # Only spend tokens once every bucket allows the request
+    for _, bucket in buckets:
+        bucket["tokens"] -= 1
+    return None
+
+# Function for cleaning up buckets that have refilled completely
+def cleanup_full_buckets():
+    now = time.time()
+    for key, bucket in list(request_buckets.items()):
+        refill = (now - bucket["last_refill"]) * bucket["capacity"] / 3600
+        if bucket["tokens"] + refill >= bucket["capacity"]:
+            del request_buckets[key]
+
+# Channel Activity (message timestamps from the last minute, keyed by channel id)
+channel_message_times = defaultdict(deque)
+
+# Function to record a message in its channel's one-minute activity window
+def record_channel_activity(channel_id):
+    now = time.time()
+    message_times = channel_message_times[channel_id]
+    message_times.append(now)
+    while now - message_times[0] > 60:
+        message_times.popleft()
+
+# Function for cleaning up channels with no activity in the last minute
+def cleanup_channel_activity():
+    now = time.time()
+    for channel_id, message_times in list(channel_message_times.items()):
+        if now - message_times[-1] > 60:
+            del channel_message_times[channel_id]
+
+# Function to check whether busy channels in a guild get their answers moved into threads
+def is_auto_thread_enabled(guild):
+    return get_guild_setting(guild, "auto_thread_enabled", AUTO_THREAD_ENABLED)
+
+# Function to pick where to answer, moving the answer into a short-lived thread when the channel is busy
+async def get_reply_channel(message):
+    if not is_auto_thread_enabled(message.guild) or not isinstance(message.channel, discord.TextChannel):
+        return message.channel
+    messages_per_minute = get_guild_setting(message.guild, "auto_thread_messages_per_minute", AUTO_THREAD_MESSAGES_PER_MINUTE)
+    if len(channel_message_times[message.channel.id]) <= messages_per_minute:
+        return message.channel
+    try:
+        return await message.create_thread(name=f"Answer for {message.author.display_name}"[:100], auto_archive_duration=60)
+    except discord.HTTPException as e:
+        logging.warning(f"Could not create a reply thread in {message.channel.name}: {e}")
+        return message.channel
+
+# Reply Tracking (triggering message id -> the bot's replies and OpenAI exchange, kept for REPLY_TRACKING_SECONDS)
+REPLY_TRACKING_ENABLED = DELETE_ORPHANED_REPLIES or REGENERATE_ON_EDIT
+tracked_replies = {}
+
+# Function for cleaning up reply tracking entries past the tracking window
+def cleanup_tracked_replies():
+    now = time.time()
+    for message_id, tracked in list(tracked_replies.items()):
+        if now - tracked["created_at"] > REPLY_TRACKING_SECONDS:
+            del tracked_replies[message_id]
+
+# New Account Challenge (users who passed the challenge since startup)
+CHALLENGE_EMOJIS = ["🍎", "🚗", "🐶", "🌙", "⚽", "🎸"]
+verified_user_ids = set()
+
+# Function to check whether a message author must pass the challenge before the bot answers
+def needs_challenge(message):
+    account_age_days = get_guild_setting(message.guild, "challenge_account_age_days", CHALLENGE_ACCOUNT_AGE_DAYS)
+    if account_age_days is None or message.author.id in verified_user_ids:
+        return False
+    return discord.utils.utcnow() - message.author.created_at < timedelta(days=account_age_days)
+
+# Challenge asking a new account to press the button showing a given emoji
+class ChallengeView(discord.ui.View):
+    def __init__(self, user_id):
+        super().__init__(timeout=120)
+        self.user_id = user_id
+        options = random.sample(CHALLENGE_EMOJIS, 4)
+        self.answer = random.choice(options)
+        for emoji in options:
+            button = discord.ui.Button(emoji=emoji, style=discord.ButtonStyle.secondary)
+            button.callback = self.make_callback(emoji)
+            self.add_item(button)
+
+    async def interaction_check(self, interaction):
+        return interaction.user.id == self.user_id
+
+    def make_callback(self, emoji):
+        async def callback(interaction):
+            self.stop()
+            if emoji == self.answer:
+                verified_user_ids.add(self.user_id)
+                await interaction.response.edit_message(content="Thanks! Mention me again and I'll answer.", view=None)
+            else:
+                await interaction.response.edit_message(content="That wasn't it. Mention me again for a new challenge.", view=None)
+        return callback
+
+# Metrics exposed on /metrics
+OPENAI_RUN_SECONDS_BUCKETS = [1, 2, 5, 10, 20, 30, 60, 120]
+metrics = {
+    "messages_handled": 0,
+    "openai_runs": defaultdict(int),
+    "openai_run_seconds": {"buckets": [0] * len(OPENAI_RUN_SECONDS_BUCKETS), "sum": 0.0, "count": 0},
+    "errors": defaultdict(int),
+    "context_warnings": 0,
+}
+
+# Time of the last run that completed, reported on /healthz
+last_openai_success = None
+# Discord gateway connection state, tracked from connection events since is_ready() stays True across disconnects
+gateway_connected = False
+
+# Function to record the duration of an OpenAI run in the latency histogram
+def observe_openai_run_seconds(seconds):
+    histogram = metrics["openai_run_seconds"]
+    for index, bound in enumerate(OPENAI_RUN_SECONDS_BUCKETS):
+        if seconds <= bound:
+            histogram["buckets"][index] += 1
+    histogram["sum"] += seconds
+    histogram["count"] += 1
+
+# Function to render the metrics in the Prometheus text format
+def render_metrics():
+    lines = [
+        "# TYPE leogpt_messages_handled_total counter",
+        f"leogpt_messages_handled_total {metrics['messages_handled']}",
+        "# TYPE leogpt_openai_runs_total counter",
+    ]
+    for status, count in metrics["openai_runs"].items():
+        lines.append(f'leogpt_openai_runs_total{{status="{status}"}} {count}')
+
+    histogram = metrics["openai_run_seconds"]
+    lines.append("# TYPE leogpt_openai_run_seconds histogram")
+    for bound, count in zip(OPENAI_RUN_SECONDS_BUCKETS, histogram["buckets"]):
+        lines.append(f'leogpt_openai_run_seconds_bucket{{le="{bound}"}} {count}')
+    lines.append(f'leogpt_openai_run_seconds_bucket{{le="+Inf"}} {histogram["count"]}')
+    lines.append(f"leogpt_openai_run_seconds_sum {histogram['sum']}")
+    lines.append(f"leogpt_openai_run_seconds_count {histogram['count']}")
+
+    lines.append("# TYPE leogpt_context_warnings_total counter")
+    lines.append(f"leogpt_context_warnings_total {metrics['context_warnings']}")
+
+    lines.append("# TYPE leogpt_errors_total counter")
+    for error_type, count in metrics["errors"].items():
+        lines.append(f'leogpt_errors_total{{type="{error_type}"}} {count}')
+    return "\n".join(lines) + "\n"
+
 # Function to create a new thread
 async def create_new_thread(identifier):
     global thread_ids
@@ -39,34 +271,105 @@ async def create_new_thread(identifier):
         logging.error(f"Error during thread creation for {identifier}: {e}")
         raise
 
+# Function to split a message into chunks that fit in a Discord message
+def split_in_chunks(message):
+    chunks = []
+    while message:
+        split_index = (message.rfind(' ', 0, MESSAGE_CHUNK_SIZE) + 1) if len(message) > MESSAGE_CHUNK_SIZE else len(message)
+        chunks.append(message[:split_index].strip())
+        message = message[split_index:]
+    return chunks
+
 # Function to send messages in chunks
 async def send_in_chunks(channel, message):
     logging.info("Sending message in chunks")
     try:
-        while message:
-            split_index = (message.rfind(' ', 0, MESSAGE_CHUNK_SIZE) + 1) if len(message) > MESSAGE_CHUNK_SIZE else len(message)
-            chunk = message[:split_index].strip()
-            await channel.send(chunk)
-            message = message[split_index:]
+        sent_messages = []
+        for chunk in split_in_chunks(message):
+            sent_messages.append(await channel.send(chunk))
         logging.info("All chunks sent successfully")
+        return sent_messages
     except Exception as e:
         logging.error(f"Error sending message in chunks: {e}")
         raise
 
+# Function to replace previously sent replies with a new message, editing them in place where possible
+async def replace_replies(replies, message):
+    chunks = split_in_chunks(message)
+    channel = replies[0].channel
+    new_replies = []
+    for index, chunk in enumerate(chunks):
+        if index < len(replies):
+            new_replies.append(await replies[index].edit(content=chunk))
+        else:
+            new_replies.append(await channel.send(chunk))
+    for reply in replies[len(chunks):]:
+        await reply.delete()
+    return new_replies
+
+# Function to render :emoji_name: tokens as the guild's custom emoji when they exist
+def enrich_guild_emojis(guild, text):
+    if guild is None:
+        return text
+    guild_emojis = {emoji.name: str(emoji) for emoji in guild.emojis if emoji.available}
+    segments = CODE_SPAN_PATTERN.split(text)
+    for index in range(0, len(segments), 2):
+        segments[index] = EMOJI_TOKEN_PATTERN.sub(lambda match: guild_emojis.get(match.group(1), match.group(0)), segments[index])
+    return "".join(segments)
+
+# Function to split leading inline directives off a message into run option overrides and the remaining text
+def extract_directives(content):
+    directives = []
+    run_overrides = {}
+    match = DIRECTIVE_PATTERN.match(content)
+    while match:
+        directive = match.group(1)
+        directives.append(f"!{directive}")
+        # None resets an override saved earlier in the conversation
+        if directive == "notools":
+            run_overrides["tools"] = []
+        elif directive == "tools":
+            run_overrides["tools"] = None
+        else:
+            model = directive.split(":", 1)[1]
+            run_overrides["model"] = None if model == "default" else model
+        content = content[match.end():]
+        match = DIRECTIVE_PATTERN.match(content)
+    return directives, run_overrides, content
+
+# Function to replace mention tokens with readable names, the way message.clean_content does
+def resolve_mentions(message, text):
+    def replace(match):
+        kind, object_id = match.group(1), int(match.group(2))
+        if kind == '#':
+            channel = bot.get_channel(object_id)
+            return f'#{channel.name}' if channel else '#deleted-channel'
+        if kind == '@&':
+            role = message.guild.get_role(object_id) if message.guild else None
+            return f'@{role.name}' if role else '@deleted-role'
+        user = discord.utils.get(message.mentions, id=object_id)
+        return f'@{user.display_name}' if user else '@deleted-user'
+    return MENTION_TOKEN_PATTERN.sub(replace, text)
+
+# Function to check whether an author holds a role allowed to use inline directives
+def can_use_directives(author):
+    return isinstance(author, discord.Member) and any(role.name in DIRECTIVE_ROLE_NAMES for role in author.roles)
+
 # Function to send message to OpenAI
 async def send_message_to_openai(clean_message, thread_id):
     try:
-        await openai_client.beta.threads.messages.create(
+        openai_message = await openai_client.beta.threads.messages.create(
             thread_id=thread_id,
             role="user",
             content=clean_message
         )
+        return openai_message.id
     except Exception as e:
         logging.error(f"Error sending message to OpenAI: {e}")
         raise
 
-# Function to check OpenAI response
-async def check_openai_response(thread_id, run_id):
+# Function to check OpenAI response, returning the run once it reaches a terminal status
+async def check_openai_response(thread_id, run_id, message):
     try:
         start_time = time.time()
         while True:
@@ -74,8 +377,22 @@ async def check_openai_response(thread_id, run_id):
                 thread_id=thread_id,
                 run_id=run_id
             )
-            if updated_run.status == "completed":
-                break
+            if updated_run.status in TERMINAL_RUN_STATUSES:
+                return updated_run
+
+            # The run is waiting on function tools the bot runs locally
+            if updated_run.status == "requires_action":
+                tool_calls = updated_run.required_action.submit_tool_outputs.tool_calls
+                tool_outputs = [
+                    {"tool_call_id": tool_call.id, "output": await execute_tool_call(tool_call, message)}
+                    for tool_call in tool_calls
+                ]
+                await openai_client.beta.threads.runs.submit_tool_outputs(
+                    thread_id=thread_id,
+                    run_id=run_id,
+                    tool_outputs=tool_outputs
+                )
+                continue
 
             elapsed_time = time.time() - start_time
             sleep_time = min(1 + elapsed_time / 10, 5)
@@ -98,8 +415,103 @@ async def retrieve_latest_response(thread_id):
         logging.error(f"Error retrieving response from OpenAI: {e}")
         raise
 
-# Function to interact with OpenAI
-async def interact_with_openai(clean_message, identifier):
+# Function to build run options, leaving unset parameters to the assistant's defaults
+def get_run_options(guild):
+    run_options = {}
+    if TEMPERATURE is not None:
+        run_options["temperature"] = TEMPERATURE
+    if TOP_P is not None:
+        run_options["top_p"] = TOP_P
+    if get_guild_setting(guild, "match_user_language", MATCH_USER_LANGUAGE):
+        run_options["additional_instructions"] = MATCH_USER_LANGUAGE_INSTRUCTIONS
+    # Passing tools replaces the assistant's own, so the local ones are only added once those are known
+    if LOCAL_TOOLS and assistant_tools is not None:
+        run_options["tools"] = assistant_tools + get_tool_definitions(LOCAL_TOOLS)
+    return run_options
+
+# Function to cancel a run that may still be active, so the thread accepts new runs
+async def cancel_run_quietly(thread_id, run_id):
+    try:
+        await openai_client.beta.threads.runs.cancel(thread_id=thread_id, run_id=run_id)
+    except (APIStatusError, APIConnectionError) as e:
+        logging.warning(f"Could not cancel run {run_id}: {e}")
+
+# Function to run the assistant, retrying transient failures and then falling back to other models
+async def run_assistant(thread_id, run_options, message):
+    global last_openai_success
+    models = [run_options.get("model")] + FALLBACK_MODELS
+    for model in models:
+        model_label = model or "assistant default"
+        model_options = dict(run_options)
+        if model is not None:
+            model_options["model"] = model
+        for attempt in range(RUN_RETRIES + 1):
+            run_start_time = time.time()
+            run = None
+            try:
+                run = await openai_client.beta.threads.runs.create(
+                    thread_id=thread_id,
+                    assistant_id=ASSISTANT_ID,
+                    **model_options
+                )
+                run = await check_openai_response(thread_id, run.id, message)
+            except (APIStatusError, APIConnectionError) as e:
+                # The SDK has already retried 429/5xx and connection errors, so move on to the next model
+                metrics["errors"][type(e).__name__] += 1
+                logging.warning(f"Run on model {model_label} failed with {type(e).__name__}: {e}")
+                if run is not None:
+                    await cancel_run_quietly(thread_id, run.id)
+                break
+            observe_openai_run_seconds(time.time() - run_start_time)
+            metrics["openai_runs"][run.status] += 1
+            if run.status == "completed":
+                last_openai_success = datetime.now()
+                return run
+
+            error_code = run.last_error.code if run.last_error else None
+            logging.warning(f"Run {run.id} on model {model_label} ended with status {run.status} ({error_code})")
+            if error_code not in TRANSIENT_RUN_ERROR_CODES or attempt == RUN_RETRIES:
+                break
+            await asyncio.sleep(2 ** attempt)
+    raise RuntimeError(f"No run completed on models {[model or 'assistant default' for model in models]}")
+
+# Function to append a note suggesting a fresh thread when the run's prompt nearly fills the context window
+async def add_context_usage_note(run, response):
+    if CONTEXT_WINDOW_TOKENS is None:
+        return response
+
+    # The run's usage sums every step, so tool calls would count the history several times; the last step's prompt holds it once
+    try:
+        steps = await openai_client.beta.threads.runs.steps.list(thread_id=run.thread_id, run_id=run.id, order="desc", limit=1)
+    except (APIStatusError, APIConnectionError) as e:
+        logging.warning(f"Could not list steps of run {run.id}: {e}")
+        return response
+    if not steps.data or steps.data[0].usage is None:
+        return response
+
+    prompt_tokens = steps.data[0].usage.prompt_tokens
+    usage_ratio = prompt_tokens / CONTEXT_WINDOW_TOKENS
+    logging.info(f"Run {run.id} ended with {prompt_tokens} prompt tokens ({usage_ratio:.0%} of the context window)")
+    if usage_ratio < CONTEXT_WARNING_RATIO:
+        return response
+
+    metrics["context_warnings"] += 1
+    return response + "\n\n*This conversation is getting close to my context limit, consider continuing in a new thread.*"
+
+# Function to remove an exchange's messages from its OpenAI thread so the conversation forgets it
+async def rewind_exchange(exchange, keep_user_message=False):
+    try:
+        run_messages = await openai_client.beta.threads.messages.list(thread_id=exchange["thread_id"], run_id=exchange["run_id"])
+        message_ids = [run_message.id for run_message in run_messages.data]
+        if not keep_user_message:
+            message_ids.append(exchange["user_message_id"])
+        for message_id in message_ids:
+            await openai_client.beta.threads.messages.delete(message_id, thread_id=exchange["thread_id"])
+    except (APIStatusError, APIConnectionError) as e:
+        logging.warning(f"Could not rewind exchange in thread {exchange['thread_id']}: {e}")
+
+# Function to interact with OpenAI, returning the response and the exchange's OpenAI ids (None on failure)
+async def interact_with_openai(clean_message, identifier, run_overrides, message):
     global thread_ids
     thread_info = thread_ids[identifier]
     thread_id = thread_info["thread_id"]
@@ -108,19 +520,33 @@ async def interact_with_openai(clean_message, identifier):
         thread_id = thread_ids[identifier]["thread_id"]
 
     try:
-        await send_message_to_openai(clean_message, thread_id)
+        if run_overrides.get("model") is not None:
+            try:
+                await openai_client.models.retrieve(run_overrides["model"])
+            except NotFoundError:
+                logging.info(f"Ignoring unknown model {run_overrides['model']} requested by {message.author.name}")
+                return f"I don't know the model {run_overrides['model']}, check the name and try again.", None
 
-        run = await openai_client.beta.threads.runs.create(
-            thread_id=thread_id,
-            assistant_id=ASSISTANT_ID
-        )
+        user_message_id = await send_message_to_openai(clean_message, thread_id)
+
+        conversation_overrides = thread_ids[identifier].setdefault("run_overrides", {})
+        overrides = {name: value for name, value in {**conversation_overrides, **run_overrides}.items() if value is not None}
+        run = await run_assistant(thread_id, {**get_run_options(message.guild), **overrides}, message)
 
-        await check_openai_response(thread_id, run.id)
-        return await retrieve_latest_response(thread_id)
+        # Directive overrides last for the whole conversation once a run succeeded with them, until its thread expires
+        for name, value in run_overrides.items():
+            if value is None:
+                conversation_overrides.pop(name, None)
+            elif name != "model" or run.model == value:
+                conversation_overrides[name] = value
+        response = await retrieve_latest_response(thread_id)
+        exchange = {"thread_id": thread_id, "user_message_id": user_message_id, "run_id": run.id}
+        return await add_context_usage_note(run, response), exchange
 
     except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
         logging.error(f"Error during OpenAI interaction: {e}")
-        return "I'm having trouble processing your request right now."
+        return "I'm having trouble processing your request right now.", None
 
 # Function for cleaning up old threads
 def cleanup_old_threads():
@@ -129,14 +555,84 @@ def cleanup_old_threads():
         if now - value["last_used"] > timedelta(hours=THREAD_INACTIVITY_TIMEOUT_HOURS):
             del thread_ids[key]
 
+# HTTP handler: /metrics
+async def handle_metrics(request):
+    return web.Response(text=render_metrics(), content_type="text/plain")
+
+# HTTP handler: /healthz, failing while the Discord gateway connection is down
+async def handle_healthz(request):
+    health = {
+        "gateway_connected": gateway_connected,
+        "gateway_latency_seconds": bot.latency if math.isfinite(bot.latency) else None,
+        "last_openai_success": last_openai_success.isoformat() if last_openai_success else None,
+    }
+    return web.json_response(health, status=200 if gateway_connected else 503)
+
+# Function to start the HTTP server
+async def start_http_server():
+    app = web.Application()
+    app.router.add_get("/metrics", handle_metrics)
+    app.router.add_get("/healthz", handle_healthz)
+    runner = web.AppRunner(app)
+    await runner.setup()
+    await web.TCPSite(runner, port=HTTP_PORT).start()
+    logging.info(f"HTTP server listening on port {HTTP_PORT}")
+
+# Function to load the assistant's own tools, which runs must keep when local tools are added
+async def load_assistant_tools():
+    global assistant_tools
+    try:
+        assistant = await openai_client.beta.assistants.retrieve(ASSISTANT_ID)
+        assistant_tools = [tool.to_dict() for tool in assistant.tools]
+    except (APIStatusError, APIConnectionError) as e:
+        logging.error(f"Could not load the assistant's tools, local tools are disabled: {e}")
+
+# Bot setup hook: runs once before connecting to Discord
+@bot.event
+async def setup_hook():
+    if HTTP_PORT is not None:
+        await start_http_server()
+    if LOCAL_TOOLS:
+        await load_assistant_tools()
+
+# Function to turn a triggering message into the prompt sent to OpenAI and its directive overrides
+def prepare_prompt(message):
+    bot_mention = f'<@{bot.user.id}>'
+    mention_prefix = bot_mention if message.content.startswith(bot_mention) else ''
+    directives, run_overrides, remaining_content = extract_directives(message.content[len(mention_prefix):])
+    if run_overrides and not can_use_directives(message.author):
+        logging.info(f"Ignoring directives {directives} from {message.author.name}: missing allowed role")
+        run_overrides = {}
+
+    clean_message = discord.utils.remove_markdown(resolve_mentions(message, mention_prefix + remaining_content))
+    if REDACT_CONTENT:
+        clean_message = redact_content(clean_message)
+    return clean_message, run_overrides
+
 # Bot event: on_ready
 @bot.event
 async def on_ready():
+    global gateway_connected
     try:
+        gateway_connected = True
         logging.info(f"Logged in as {bot.user.name}")
     except Exception as e:
         logging.error(f"Error in on_ready: {e}")
 
+# Bot event: on_resumed
+@bot.event
+async def on_resumed():
+    global gateway_connected
+    gateway_connected = True
+    logging.info("Gateway session resumed")
+
+# Bot event: on_disconnect
+@bot.event
+async def on_disconnect():
+    global gateway_connected
+    gateway_connected = False
+    logging.warning("Disconnected from the Discord gateway")
+
 # Bot event: on_message
 @bot.event
 async def on_message(message):
@@ -148,19 +644,170 @@ async def on_message(message):
         thread_ids[identifier]["last_used"] = datetime.now()
 
         cleanup_old_threads()
+        cleanup_full_buckets()
+
+        if REPLY_TRACKING_ENABLED:
+            cleanup_tracked_replies()
+
+        if is_auto_thread_enabled(message.guild):
+            record_channel_activity(message.channel.id)
+            cleanup_channel_activity()
 
         bot_mention = f'<@{bot.user.id}>'
-        if message.content.startswith(bot_mention):
-            clean_message = discord.utils.remove_markdown(message.clean_content)
+        is_dm = isinstance(message.channel, discord.DMChannel)
+        if (DM_MODE_ENABLED and is_dm) or message.content.startswith(bot_mention):
+            clean_message, run_overrides = prepare_prompt(message)
             logging.info(f"Received message from {message.author.name}: {clean_message}")
 
-            async with message.channel.typing():
-                response = await interact_with_openai(clean_message, identifier)
+            if MAINTENANCE_MODE:
+                await message.channel.send("I'm down for maintenance right now, please try again later.")
+                return
+
+            if needs_challenge(message):
+                view = ChallengeView(message.author.id)
+                logging.info(f"Sending new account challenge to {message.author.name}")
+                await message.channel.send(f"{message.author.mention} your account is quite new, please press {view.answer} first.", view=view)
+                return
+
+            exhausted_scope = check_rate_limits(message)
+            if exhausted_scope == "user":
+                logging.info(f"User rate limit exceeded for {message.author.name}")
+                await message.channel.send("You're sending requests a bit too fast, please try again later.")
+                return
+            if exhausted_scope == "guild":
+                logging.info(f"Guild rate limit exceeded for {message.guild.name}")
+                await message.channel.send("This server has used up its requests for now, please try again later.")
+                return
+
+            # An answer moved into a new thread starts that thread's conversation, so follow-ups there keep context
+            reply_channel = await get_reply_channel(message)
+            identifier = reply_channel.id
+            metrics["messages_handled"] += 1
+            async with reply_channel.typing():
+                response, exchange = await interact_with_openai(clean_message, identifier, run_overrides, message)
                 logging.info(f"OpenAI response: {response}")
-            
-            await send_in_chunks(message.channel, response)
+
+            response = enrich_guild_emojis(message.guild, response)
+            reply_messages = await send_in_chunks(reply_channel, response)
+            answer = {
+                "trigger": message,
+                "replies": reply_messages,
+                "created_at": time.time(),
+                "content": message.content,
+                "identifier": identifier,
+                "exchange": exchange,
+            }
+            # The last answer of each conversation can be regenerated with a reaction
+            thread_ids[identifier]["last_answer"] = answer
+            if REPLY_TRACKING_ENABLED:
+                tracked_replies[message.id] = answer
     except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
         logging.error(f"Error in on_message for {message.content}: {e}")
 
+# Function to delete the bot's replies to a deleted triggering message and forget that exchange
+async def delete_tracked_replies(message_id):
+    tracked = tracked_replies.pop(message_id, None)
+    if not DELETE_ORPHANED_REPLIES or tracked is None or time.time() - tracked["created_at"] > REPLY_TRACKING_SECONDS:
+        return
+
+    for reply in tracked["replies"]:
+        try:
+            await reply.delete()
+        except discord.NotFound:
+            pass
+    if tracked["exchange"] is not None:
+        await rewind_exchange(tracked["exchange"])
+    logging.info(f"Deleted {len(tracked['replies'])} replies to deleted message {message_id}")
+
+# Bot event: on_raw_message_delete, removing the bot's reply when its triggering message is deleted
+@bot.event
+async def on_raw_message_delete(payload):
+    try:
+        await delete_tracked_replies(payload.message_id)
+    except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
+        logging.error(f"Error in on_raw_message_delete for {payload.message_id}: {e}")
+
+# Bot event: on_raw_bulk_message_delete, the same for messages removed by a purge
+@bot.event
+async def on_raw_bulk_message_delete(payload):
+    try:
+        for message_id in payload.message_ids:
+            await delete_tracked_replies(message_id)
+    except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
+        logging.error(f"Error in on_raw_bulk_message_delete in channel {payload.channel_id}: {e}")
+
+# Bot event: on_raw_message_edit, regenerating the bot's reply when its triggering message is edited
+@bot.event
+async def on_raw_message_edit(payload):
+    try:
+        tracked = tracked_replies.get(payload.message_id)
+        if not REGENERATE_ON_EDIT or tracked is None or time.time() - tracked["created_at"] > REPLY_TRACKING_SECONDS:
+            return
+
+        channel = bot.get_channel(payload.channel_id) or await bot.fetch_channel(payload.channel_id)
+        message = await channel.fetch_message(payload.message_id)
+        # Link previews arrive as edits too, so only regenerate when the text changed
+        if message.content == tracked["content"] or MAINTENANCE_MODE or check_rate_limits(message) is not None:
+            return
+        tracked["content"] = message.content
+
+        clean_message, run_overrides = prepare_prompt(message)
+        logging.info(f"Regenerating reply to edited message from {message.author.name}: {clean_message}")
+        if tracked["exchange"] is not None:
+            await rewind_exchange(tracked["exchange"])
+
+        reply_channel = tracked["replies"][0].channel
+        async with reply_channel.typing():
+            response, tracked["exchange"] = await interact_with_openai(clean_message, tracked["identifier"], run_overrides, message)
+        response = enrich_guild_emojis(message.guild, response)
+        tracked["replies"] = await replace_replies(tracked["replies"], response)
+    except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
+        logging.error(f"Error in on_raw_message_edit for {payload.message_id}: {e}")
+
+# Function to rerun the last answer of a conversation on the same history and replace its replies
+async def regenerate_answer(answer):
+    exchange = answer["exchange"]
+    await rewind_exchange(exchange, keep_user_message=True)
+    try:
+        run_options = {**get_run_options(answer["trigger"].guild), **thread_ids[answer["identifier"]].get("run_overrides", {})}
+        if REGENERATE_TEMPERATURE is not None:
+            run_options["temperature"] = REGENERATE_TEMPERATURE
+        run = await run_assistant(exchange["thread_id"], run_options, answer["trigger"])
+        response = await add_context_usage_note(run, await retrieve_latest_response(exchange["thread_id"]))
+        answer["exchange"] = {**exchange, "run_id": run.id}
+    except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
+        logging.error(f"Error during OpenAI regeneration: {e}")
+        response = "I'm having trouble processing your request right now."
+        answer["exchange"] = None
+    response = enrich_guild_emojis(answer["trigger"].guild, response)
+    answer["replies"] = await replace_replies(answer["replies"], response)
+
+# Bot event: on_raw_reaction_add, regenerating the last answer when its requester reacts with 🔁
+@bot.event
+async def on_raw_reaction_add(payload):
+    try:
+        if not REGENERATE_REACTION_ENABLED or str(payload.emoji) != "🔁" or payload.user_id == bot.user.id:
+            return
+        answer = thread_ids[payload.channel_id].get("last_answer") if payload.channel_id in thread_ids else None
+        if answer is None or answer["exchange"] is None or answer["trigger"].author.id != payload.user_id:
+            return
+        if payload.message_id not in [reply.id for reply in answer["replies"]]:
+            return
+        if MAINTENANCE_MODE or check_rate_limits(answer["trigger"]) is not None:
+            return
+
+        logging.info(f"Regenerating last answer in {payload.channel_id} for {answer['trigger'].author.name}")
+        metrics["messages_handled"] += 1
+        async with answer["replies"][0].channel.typing():
+            await regenerate_answer(answer)
+    except Exception as e:
+        metrics["errors"][type(e).__name__] += 1
+        logging.error(f"Error in on_raw_reaction_add for {payload.message_id}: {e}")
+
 # Running the bot
 bot.run(DISCORD_BOT_TOKEN)
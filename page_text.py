@@ -0,0 +1,46 @@
+import re
+from html.parser import HTMLParser
+
+# Elements whose content is never readable text
+SKIPPED_TAGS = {"script", "style", "noscript", "template", "svg", "head"}
+# Elements that start a new line of text
+BLOCK_TAGS = {"p", "div", "br", "li", "tr", "h1", "h2", "h3", "h4", "h5", "h6", "section", "article", "header", "footer", "blockquote", "pre", "title"}
+
+# HTML parser collecting the readable text of a page
+class PageTextParser(HTMLParser):
+    def __init__(self):
+        super().__init__(convert_charrefs=True)
+        self.parts = []
+        self.skipped_depth = 0
+        self.title = None
+        self.in_title = False
+
+    def handle_starttag(self, tag, attrs):
+        if tag == "title":
+            self.in_title = True
+        elif tag in SKIPPED_TAGS:
+            self.skipped_depth += 1
+        elif tag in BLOCK_TAGS and not self.skipped_depth:
+            self.parts.append("\n")
+
+    def handle_endtag(self, tag):
+        if tag == "title":
+            self.in_title = False
+        elif tag in SKIPPED_TAGS and self.skipped_depth:
+            self.skipped_depth -= 1
+        elif tag in BLOCK_TAGS and not self.skipped_depth:
+            self.parts.append("\n")
+
+    def handle_data(self, data):
+        if self.in_title:
+            self.title = (self.title or "") + data.strip()
+        elif not self.skipped_depth:
+            self.parts.append(data)
+
+# Function to strip a page's HTML down to its title and readable text
+def html_to_text(html):
+    parser = PageTextParser()
+    parser.feed(html)
+    parser.close()
+    lines = (re.sub(r'\s+', ' ', line).strip() for line in "".join(parser.parts).split("\n"))
+    return parser.title, "\n".join(line for line in lines if line)
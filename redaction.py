@@ -0,0 +1,24 @@
+import re
+
+# Redaction patterns applied before content is sent to OpenAI
+REDACTION_PATTERNS = [
+    # The domain ends on a word character so a trailing sentence period is kept
+    (re.compile(r'[\w.+-]+@[\w-]+(?:\.[\w-]+)+'), '[REDACTED EMAIL]'),
+    (re.compile(r'\b(?:sk|pk|rk|ghp|gho|xox[abp])[-_][A-Za-z0-9_-]{16,}\b'), '[REDACTED TOKEN]'),
+    # Discord bot tokens: base64 user id, base64 timestamp and HMAC separated by dots
+    (re.compile(r'\b[MNO][A-Za-z\d_-]{23,27}\.[A-Za-z\d_-]{6}\.[A-Za-z\d_-]{27,}\b'), '[REDACTED TOKEN]'),
+    # International numbers: a leading + and at least 8 digits, in groups of 2+ digits after the first
+    (re.compile(r'(?<![\w+])\+(?=(?:[ .()-]*\d){8})\d{1,3}[ .-]?\(?\d{1,4}\)?(?:[ .-]?\(?\d{2,4}\)?){1,4}(?![\w.-]|[ .-]?\d)'), '[REDACTED PHONE]'),
+    # European national numbers: a leading 0 and pairs of digits, e.g. 06 12 34 56 78 or 06.12.34.56.78
+    (re.compile(r'(?<![\w.+-])0\d(?:[ .-]\d{2}){4}(?![\w.-]|[ .-]?\d)'), '[REDACTED PHONE]'),
+    # European national numbers: a leading 0 area code and longer groups, e.g. 020 7946 0958 or 030 12345678
+    (re.compile(r'(?<![\w.+-])0\d{2,4}[ /-](?:\d{3,4}[ -]\d{3,4}|\d{6,8})(?![\w.-]|[ .-]?\d)'), '[REDACTED PHONE]'),
+    # North American numbers: 3-3-4 digits, e.g. 555-123-4567, 555.123.4567 or (555) 123-4567
+    (re.compile(r'(?<![\w.-])(?:\(\d{3}\) ?|\d{3}[.-])\d{3}[.-]\d{4}(?![\w.-])'), '[REDACTED PHONE]'),
+]
+
+# Function to redact sensitive content
+def redact_content(text):
+    for pattern, replacement in REDACTION_PATTERNS:
+        text = pattern.sub(replacement, text)
+    return text
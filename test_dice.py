@@ -0,0 +1,38 @@
+import random
+import unittest
+
+from dice import parse_dice_notation, roll_dice
+
+
+class RollDiceTest(unittest.TestCase):
+    def test_parses_standard_notation(self):
+        self.assertEqual(parse_dice_notation("2d20+5"), ([(1, 2, 20)], 5))
+        self.assertEqual(parse_dice_notation("d6"), ([(1, 1, 6)], 0))
+        self.assertEqual(parse_dice_notation("1D8 + 2d6 - 1"), ([(1, 1, 8), (1, 2, 6)], -1))
+        self.assertEqual(parse_dice_notation("4d6-1d4"), ([(1, 4, 6), (-1, 1, 4)], 0))
+
+    def test_rejects_invalid_notation(self):
+        for notation in ["", "5", "2d", "d1", "2d20 5", "101d6", "60d6+60d6", "2x6", "d20+"]:
+            with self.assertRaises(ValueError, msg=notation):
+                parse_dice_notation(notation)
+        with self.assertRaises(ValueError):
+            roll_dice("d20", mode="lucky")
+
+    def test_rolls_within_bounds_and_totals_breakdown(self):
+        result = roll_dice("3d6+2", rng=random.Random(1))
+        self.assertEqual(len(result["attempts"]), 1)
+        attempt = result["attempts"][0]
+        self.assertEqual(len(attempt["rolls"][0]["results"]), 3)
+        self.assertTrue(all(1 <= value <= 6 for value in attempt["rolls"][0]["results"]))
+        self.assertEqual(result["total"], sum(attempt["rolls"][0]["results"]) + 2)
+
+    def test_advantage_and_disadvantage_keep_the_best_and_worst_attempt(self):
+        advantage = roll_dice("d20", mode="advantage", rng=random.Random(2))
+        disadvantage = roll_dice("d20", mode="disadvantage", rng=random.Random(2))
+        self.assertEqual(len(advantage["attempts"]), 2)
+        self.assertEqual(advantage["total"], max(attempt["total"] for attempt in advantage["attempts"]))
+        self.assertEqual(disadvantage["total"], min(attempt["total"] for attempt in disadvantage["attempts"]))
+
+
+if __name__ == "__main__":
+    unittest.main()
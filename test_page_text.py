@@ -0,0 +1,23 @@
+import unittest
+
+from page_text import html_to_text
+
+
+class HtmlToTextTest(unittest.TestCase):
+    def test_keeps_title_and_readable_text(self):
+        html = """
+            <html><head><title>Release notes</title><style>p { color: red; }</style></head>
+            <body><h1>Version  2.0</h1><p>Faster &amp; smaller.<br>No more bugs.</p>
+            <ul><li>One</li><li>Two</li></ul></body></html>
+        """
+        title, text = html_to_text(html)
+        self.assertEqual(title, "Release notes")
+        self.assertEqual(text, "Version 2.0\nFaster & smaller.\nNo more bugs.\nOne\nTwo")
+
+    def test_drops_scripts_and_hidden_markup(self):
+        html = "<p>Hello</p><script>alert('x')</script><noscript>Enable JS</noscript><svg><text>logo</text></svg><p>World</p>"
+        self.assertEqual(html_to_text(html), (None, "Hello\nWorld"))
+
+
+if __name__ == "__main__":
+    unittest.main()
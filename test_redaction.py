@@ -0,0 +1,43 @@
+import unittest
+
+from redaction import redact_content
+
+
+class RedactContentTest(unittest.TestCase):
+    def test_redacts_sensitive_values(self):
+        self.assertEqual(redact_content("mail me at a.b@example.com"), "mail me at [REDACTED EMAIL]")
+        self.assertEqual(redact_content("write to a@b.co.uk."), "write to [REDACTED EMAIL].")
+        self.assertEqual(
+            redact_content("token MTIzNDU2Nzg5MDEyMzQ1Njc4.GAbCdE.abcdefghijklmnopqrstuvwxyz0123456789"),
+            "token [REDACTED TOKEN]",
+        )
+        self.assertEqual(redact_content("key sk-abcdefghijklmnop1234"), "key [REDACTED TOKEN]")
+        self.assertEqual(redact_content("call +33 6 12 34 56 78 now"), "call [REDACTED PHONE] now")
+        self.assertEqual(redact_content("call +1 (555) 123-4567"), "call [REDACTED PHONE]")
+        self.assertEqual(redact_content("call 555-123-4567"), "call [REDACTED PHONE]")
+        self.assertEqual(redact_content("call (555) 123-4567"), "call [REDACTED PHONE]")
+        self.assertEqual(redact_content("appelle le 06 12 34 56 78"), "appelle le [REDACTED PHONE]")
+        self.assertEqual(redact_content("appelle le 06.12.34.56.78"), "appelle le [REDACTED PHONE]")
+        self.assertEqual(redact_content("ring 020 7946 0958"), "ring [REDACTED PHONE]")
+        self.assertEqual(redact_content("ruf 030 12345678 an"), "ruf [REDACTED PHONE] an")
+
+    def test_leaves_numbers_that_are_not_phone_numbers(self):
+        for text in [
+            "what is 123456789 * 3?",
+            "meeting on 2024-01-15 12:30",
+            "ping 192.168.100.1",
+            "pi is 3.14159265",
+            "foo==1.22.333.4",
+            "sum 1 2 3 4 5 6 7 8 9",
+            "x +1 2 3",
+            "sum +1 2 3 4 5 6 7 8 9",
+            "sum 0 1 2 3 4 5 6 7 8 9",
+            "in 0.5 seconds",
+            "007 is a spy",
+            "version 0.12.34",
+        ]:
+            self.assertEqual(redact_content(text), text)
+
+
+if __name__ == "__main__":
+    unittest.main()
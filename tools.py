@@ -0,0 +1,347 @@
+import aiohttp
+import asyncio
+import discord
+import ipaddress
+import json
+import logging
+import re
+import time
+from config import config
+from datetime import timedelta
+from urllib.parse import quote, urljoin, urlsplit
+
+from dice import roll_dice
+from page_text import html_to_text
+
+# Optional settings
+FETCH_URL_BLOCKED_DOMAINS = getattr(config, "fetch_url_blocked_domains", [])
+FETCH_URL_MAX_BYTES = getattr(config, "fetch_url_max_bytes", 1_000_000)
+FETCH_URL_TIMEOUT_SECONDS = getattr(config, "fetch_url_timeout_seconds", 10)
+FETCH_URL_MAX_CHARACTERS = getattr(config, "fetch_url_max_characters", 8000)
+FETCH_URL_MAX_REDIRECTS = 5
+CURRENCY_RATES_CACHE_SECONDS = getattr(config, "currency_rates_cache_seconds", 3600)
+
+# Exchange rates for fiat and crypto currencies, keyed by base currency
+CURRENCY_RATES_URL = "https://api.coinbase.com/v2/exchange-rates"
+currency_rates_cache = {}
+
+# Wikipedia asks API clients to identify themselves
+WIKIPEDIA_HEADERS = {"User-Agent": "leogpt Discord bot (https://github.com/excoffierleonard/leogpt)"}
+WIKIPEDIA_LANGUAGE_PATTERN = re.compile(r'[a-z]{2,3}(?:-[a-z]+)?')
+
+# Matches a Discord message link, capturing the guild ("@me" in DMs), channel and message ids
+MESSAGE_LINK_PATTERN = re.compile(r'https://(?:(?:ptb|canary)\.)?discord(?:app)?\.com/channels/(\d+|@me)/(\d+)/(\d+)')
+
+# Reactions used to vote on polls when the installed discord.py has no native polls
+POLL_OPTION_EMOJIS = ["1️⃣", "2️⃣", "3️⃣", "4️⃣", "5️⃣", "6️⃣", "7️⃣", "8️⃣", "9️⃣", "🔟"]
+
+# Function tool: roll dice locally so results are genuinely random
+async def roll_dice_tool(arguments, message):
+    return roll_dice(arguments["notation"], arguments.get("mode", "normal"))
+
+# Function tool: post a poll in the triggering message's channel
+async def create_poll_tool(arguments, message):
+    question = arguments["question"]
+    options = arguments["options"]
+    duration_hours = arguments.get("duration_hours", 24)
+    if not 2 <= len(options) <= len(POLL_OPTION_EMOJIS):
+        raise ValueError(f"A poll needs between 2 and {len(POLL_OPTION_EMOJIS)} options")
+    if not 1 <= duration_hours <= 768:
+        raise ValueError("A poll lasts between 1 and 768 hours")
+
+    if hasattr(discord, "Poll"):
+        poll = discord.Poll(question=question, duration=timedelta(hours=duration_hours))
+        for option in options:
+            poll.add_answer(text=option)
+        poll_message = await message.channel.send(poll=poll)
+        return {"message_link": poll_message.jump_url, "kind": "native poll"}
+
+    # Reaction polls can't close by themselves, so the duration is only shown
+    lines = [f"**{question}**"] + [f"{emoji} {option}" for emoji, option in zip(POLL_OPTION_EMOJIS, options)]
+    lines.append(f"*Vote with the reactions below, the poll runs for {duration_hours} hours.*")
+    poll_message = await message.channel.send("\n".join(lines))
+    for emoji in POLL_OPTION_EMOJIS[:len(options)]:
+        await poll_message.add_reaction(emoji)
+    return {"message_link": poll_message.jump_url, "kind": "reaction poll"}
+
+# Function to refuse URLs on blocked domains or pointing at private, loopback or link-local addresses
+async def check_fetchable_url(url):
+    parts = urlsplit(url)
+    if parts.scheme not in ("http", "https") or not parts.hostname:
+        raise ValueError("Only http and https URLs can be fetched")
+    host = parts.hostname.lower().rstrip(".")
+    if any(host == domain or host.endswith("." + domain) for domain in FETCH_URL_BLOCKED_DOMAINS):
+        raise ValueError(f"The domain {host} is blocked")
+
+    addresses = await asyncio.get_running_loop().getaddrinfo(host, parts.port or (443 if parts.scheme == "https" else 80))
+    if not addresses or not all(ipaddress.ip_address(address[4][0]).is_global for address in addresses):
+        raise ValueError(f"The host {host} is not a public address")
+
+# Function tool: download a page and return its readable text
+async def fetch_url_tool(arguments, message):
+    url = arguments["url"]
+    timeout = aiohttp.ClientTimeout(total=FETCH_URL_TIMEOUT_SECONDS)
+    async with aiohttp.ClientSession(timeout=timeout) as session:
+        # Redirects are followed by hand so every hop is checked
+        for _ in range(FETCH_URL_MAX_REDIRECTS + 1):
+            await check_fetchable_url(url)
+            async with session.get(url, allow_redirects=False) as response:
+                if response.status in (301, 302, 303, 307, 308) and "Location" in response.headers:
+                    url = urljoin(url, response.headers["Location"])
+                    continue
+                if response.status >= 400:
+                    raise ValueError(f"The page answered with HTTP {response.status}")
+                if response.content_type not in ("text/html", "text/plain", "application/xhtml+xml"):
+                    raise ValueError(f"Unsupported content type {response.content_type}")
+
+                body = bytearray()
+                async for chunk in response.content.iter_chunked(65536):
+                    body.extend(chunk)
+                    if len(body) >= FETCH_URL_MAX_BYTES:
+                        break
+                page = bytes(body[:FETCH_URL_MAX_BYTES]).decode(response.charset or "utf-8", errors="replace")
+                break
+        else:
+            raise ValueError("Too many redirects")
+
+    title, text = html_to_text(page) if response.content_type != "text/plain" else (None, page)
+    return {
+        "url": url,
+        "title": title,
+        "text": text[:FETCH_URL_MAX_CHARACTERS],
+        "truncated": len(body) >= FETCH_URL_MAX_BYTES or len(text) > FETCH_URL_MAX_CHARACTERS,
+    }
+
+# Function to get the exchange rates from a base currency, cached for CURRENCY_RATES_CACHE_SECONDS
+async def get_currency_rates(base):
+    cached = currency_rates_cache.get(base)
+    if cached is not None and time.time() - cached["fetched_at"] < CURRENCY_RATES_CACHE_SECONDS:
+        return cached
+
+    timeout = aiohttp.ClientTimeout(total=FETCH_URL_TIMEOUT_SECONDS)
+    async with aiohttp.ClientSession(timeout=timeout) as session:
+        async with session.get(CURRENCY_RATES_URL, params={"currency": base}) as response:
+            if response.status >= 400:
+                raise ValueError(f"Unknown currency {base} or rates unavailable (HTTP {response.status})")
+            data = await response.json()
+
+    cached = {"rates": data["data"]["rates"], "fetched_at": time.time()}
+    currency_rates_cache[base] = cached
+    return cached
+
+# Function tool: convert an amount between fiat or crypto currencies at current rates
+async def convert_currency_tool(arguments, message):
+    amount = float(arguments["amount"])
+    source = arguments["from"].upper()
+    target = arguments["to"].upper()
+    cached = await get_currency_rates(source)
+    if target not in cached["rates"]:
+        raise ValueError(f"No rate from {source} to {target}")
+
+    rate = float(cached["rates"][target])
+    return {
+        "amount": amount,
+        "from": source,
+        "to": target,
+        "rate": rate,
+        "converted": amount * rate,
+        "rates_as_of": time.strftime("%Y-%m-%d %H:%M UTC", time.gmtime(cached["fetched_at"])),
+    }
+
+# Function tool: find the Wikipedia article best matching a query and return its summary and URL
+async def wiki_lookup_tool(arguments, message):
+    language = arguments.get("language", "en").lower()
+    if not WIKIPEDIA_LANGUAGE_PATTERN.fullmatch(language):
+        raise ValueError(f"Invalid Wikipedia language code {language}")
+
+    base_url = f"https://{language}.wikipedia.org"
+    timeout = aiohttp.ClientTimeout(total=FETCH_URL_TIMEOUT_SECONDS)
+    async with aiohttp.ClientSession(timeout=timeout, headers=WIKIPEDIA_HEADERS) as session:
+        async with session.get(f"{base_url}/w/rest.php/v1/search/title", params={"q": arguments["query"], "limit": 1}) as response:
+            response.raise_for_status()
+            pages = (await response.json())["pages"]
+        if not pages:
+            return {"found": False}
+
+        async with session.get(f"{base_url}/api/rest_v1/page/summary/{quote(pages[0]['key'], safe='')}") as response:
+            response.raise_for_status()
+            summary = await response.json()
+
+    return {
+        "found": True,
+        "title": summary["title"],
+        "summary": summary.get("extract", ""),
+        "url": summary["content_urls"]["desktop"]["page"],
+    }
+
+# Function to find the message a link points to, limited to channels the requester can read in the same guild
+async def resolve_message_link(link, message):
+    match = MESSAGE_LINK_PATTERN.fullmatch(link.strip())
+    if match is None:
+        raise ValueError("Not a Discord message link")
+    guild_id, channel_id, message_id = match.group(1), int(match.group(2)), int(match.group(3))
+
+    if message.guild is None:
+        if guild_id != "@me" or channel_id != message.channel.id:
+            raise ValueError("Only messages from this conversation can be reached")
+        return await message.channel.fetch_message(message_id)
+
+    channel = message.guild.get_channel_or_thread(channel_id)
+    if guild_id != str(message.guild.id) or channel is None:
+        raise ValueError("Only messages from this server can be reached")
+    if not channel.permissions_for(message.author).read_message_history:
+        raise ValueError("The requester can't read that channel")
+    return await channel.fetch_message(message_id)
+
+# Function tool: react to the triggering message, or a linked one, with a unicode or guild emoji
+async def add_reaction_tool(arguments, message):
+    target = message
+    if arguments.get("message_link"):
+        target = await resolve_message_link(arguments["message_link"], message)
+
+    emoji = arguments["emoji"].strip()
+    if message.guild is not None:
+        guild_emoji = discord.utils.get(message.guild.emojis, name=emoji.strip(":"))
+        if guild_emoji is not None:
+            emoji = guild_emoji
+    await target.add_reaction(emoji)
+    return {"reacted": True, "emoji": str(emoji), "message_link": target.jump_url}
+
+# Local function tools the assistant can call, keyed by name: (OpenAI tool definition, async handler(arguments, message))
+TOOLS = {
+    "roll_dice": (
+        {
+            "type": "function",
+            "function": {
+                "name": "roll_dice",
+                "description": "Roll dice with a true random source and return every die result and the total. Use this instead of inventing results.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "notation": {"type": "string", "description": "Standard dice notation, e.g. 2d20+5, d6 or 4d6-1d4"},
+                        "mode": {
+                            "type": "string",
+                            "enum": ["normal", "advantage", "disadvantage"],
+                            "description": "Roll twice and keep the higher (advantage) or lower (disadvantage) total",
+                        },
+                    },
+                    "required": ["notation"],
+                },
+            },
+        },
+        roll_dice_tool,
+    ),
+    "create_poll": (
+        {
+            "type": "function",
+            "function": {
+                "name": "create_poll",
+                "description": "Post a Discord poll in the current channel and return a link to it.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "question": {"type": "string", "description": "The poll question, at most 300 characters"},
+                        "options": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Between 2 and 10 answers, at most 55 characters each",
+                        },
+                        "duration_hours": {"type": "integer", "description": "How long the poll stays open, 1 to 768 hours, 24 by default"},
+                    },
+                    "required": ["question", "options"],
+                },
+            },
+        },
+        create_poll_tool,
+    ),
+    "fetch_url": (
+        {
+            "type": "function",
+            "function": {
+                "name": "fetch_url",
+                "description": "Download a web page and return its title and readable text, e.g. to read a link the user shared.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "url": {"type": "string", "description": "The http or https URL to read"},
+                    },
+                    "required": ["url"],
+                },
+            },
+        },
+        fetch_url_tool,
+    ),
+    "convert_currency": (
+        {
+            "type": "function",
+            "function": {
+                "name": "convert_currency",
+                "description": "Convert an amount between currencies at current exchange rates. Supports fiat (EUR, USD, CAD...) and major crypto (BTC, ETH...).",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "amount": {"type": "number"},
+                        "from": {"type": "string", "description": "Currency code to convert from, e.g. EUR"},
+                        "to": {"type": "string", "description": "Currency code to convert to, e.g. CAD"},
+                    },
+                    "required": ["amount", "from", "to"],
+                },
+            },
+        },
+        convert_currency_tool,
+    ),
+    "wiki_lookup": (
+        {
+            "type": "function",
+            "function": {
+                "name": "wiki_lookup",
+                "description": "Look up a topic on Wikipedia and return the best matching article's summary and URL to cite.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "query": {"type": "string", "description": "The topic to look up"},
+                        "language": {"type": "string", "description": "Wikipedia language code, en by default"},
+                    },
+                    "required": ["query"],
+                },
+            },
+        },
+        wiki_lookup_tool,
+    ),
+    "add_reaction": (
+        {
+            "type": "function",
+            "function": {
+                "name": "add_reaction",
+                "description": "React with an emoji to the user's message, or to another message of this server given its link.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "emoji": {"type": "string", "description": "A unicode emoji such as 🎉, or the name of one of the server's custom emoji"},
+                        "message_link": {"type": "string", "description": "Link to the message to react to, the user's message when left out"},
+                    },
+                    "required": ["emoji"],
+                },
+            },
+        },
+        add_reaction_tool,
+    ),
+}
+
+# Function to get the OpenAI definitions of the enabled local tools
+def get_tool_definitions(names):
+    return [TOOLS[name][0] for name in names if name in TOOLS]
+
+# Function to run one tool call from a run, returning its JSON output; failures are reported to the assistant
+async def execute_tool_call(tool_call, message):
+    name = tool_call.function.name
+    try:
+        if name not in TOOLS:
+            raise ValueError(f"Unknown tool: {name}")
+        arguments = json.loads(tool_call.function.arguments or "{}")
+        output = await TOOLS[name][1](arguments, message)
+        logging.info(f"Tool {name} called with {arguments}: {output}")
+    except Exception as e:
+        logging.warning(f"Tool call {name} failed: {e}")
+        output = {"error": str(e)}
+    return json.dumps(output)